@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::server::Message;
+use super::world::World;
+
+/// The kind of token a command node expects at its position in the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    Literal,
+    Int,
+    Float,
+    String,
+    Vec3,
+}
+
+/// A parsed argument, tagged by the `ArgType` that produced it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgValue {
+    Literal(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Vec3(f32, f32, f32),
+}
+
+pub type CommandHandler = fn(usize, &[ArgValue], &mut World);
+
+/// One node in a command tree: either a literal keyword (e.g. `give`) or
+/// a typed argument slot. A node only resolves a command if it carries a
+/// `handler`, so partial paths (e.g. `/time` with no node set up to take
+/// no arguments) correctly fall through to a usage error.
+pub struct CommandNode {
+    name: String,
+    arg_type: ArgType,
+    handler: Option<CommandHandler>,
+    children: Vec<CommandNode>,
+}
+
+impl CommandNode {
+    fn new(name: &str, arg_type: ArgType) -> Self {
+        CommandNode {
+            name: name.to_owned(),
+            arg_type,
+            handler: None,
+            children: Vec::new(),
+        }
+    }
+
+    /// Append a child argument/literal node and return it, so calls can be
+    /// chained to describe a command's full argument sequence.
+    pub fn add_child(&mut self, name: &str, arg_type: ArgType) -> &mut CommandNode {
+        self.children.push(CommandNode::new(name, arg_type));
+        self.children.last_mut().unwrap()
+    }
+
+    pub fn set_handler(&mut self, handler: CommandHandler) -> &mut CommandNode {
+        self.handler = Some(handler);
+        self
+    }
+
+    /// How this node reads in a usage string: the bare keyword for a
+    /// literal, or `<name:Type>` for a typed argument slot.
+    fn hint(&self) -> String {
+        match self.arg_type {
+            ArgType::Literal => self.name.clone(),
+            other => format!("<{}:{:?}>", self.name, other),
+        }
+    }
+
+    /// Recurse into every handler-bearing path under this node, appending
+    /// its full usage string (e.g. `give <item:String> <count:Int>`) to
+    /// `usages`.
+    fn collect_usages(&self, prefix: &str, usages: &mut Vec<String>) {
+        let path = if prefix.is_empty() {
+            self.hint()
+        } else {
+            format!("{} {}", prefix, self.hint())
+        };
+
+        if self.handler.is_some() {
+            usages.push(path.clone());
+        }
+
+        for child in &self.children {
+            child.collect_usages(&path, usages);
+        }
+    }
+
+    fn try_consume<'a>(&self, tokens: &'a [&'a str]) -> Option<(ArgValue, &'a [&'a str])> {
+        if tokens.is_empty() {
+            return None;
+        }
+
+        match self.arg_type {
+            ArgType::Literal => (tokens[0] == self.name)
+                .then(|| (ArgValue::Literal(tokens[0].to_owned()), &tokens[1..])),
+            ArgType::Int => tokens[0]
+                .parse::<i64>()
+                .ok()
+                .map(|n| (ArgValue::Int(n), &tokens[1..])),
+            ArgType::Float => tokens[0]
+                .parse::<f64>()
+                .ok()
+                .map(|n| (ArgValue::Float(n), &tokens[1..])),
+            ArgType::String => Some((ArgValue::String(tokens[0].to_owned()), &tokens[1..])),
+            ArgType::Vec3 => {
+                if tokens.len() < 3 {
+                    return None;
+                }
+
+                let x = tokens[0].parse::<f32>().ok()?;
+                let y = tokens[1].parse::<f32>().ok()?;
+                let z = tokens[2].parse::<f32>().ok()?;
+
+                Some((ArgValue::Vec3(x, y, z), &tokens[3..]))
+            }
+        }
+    }
+
+    fn walk(
+        &self,
+        id: usize,
+        tokens: &[&str],
+        args: &mut Vec<ArgValue>,
+        world: &mut World,
+        position: usize,
+    ) -> Result<(), CommandError> {
+        if tokens.is_empty() {
+            return match self.handler {
+                Some(handler) => {
+                    handler(id, args, world);
+                    Ok(())
+                }
+                None => Err(CommandError {
+                    position,
+                    expected: self.children.iter().map(|c| c.hint()).collect(),
+                }),
+            };
+        }
+
+        for child in &self.children {
+            if let Some((value, rest)) = child.try_consume(tokens) {
+                let consumed = tokens.len() - rest.len();
+                args.push(value);
+                return child.walk(id, rest, args, world, position + consumed);
+            }
+        }
+
+        Err(CommandError {
+            position,
+            expected: self.children.iter().map(|c| c.hint()).collect(),
+        })
+    }
+}
+
+/// Where a command string stopped matching, and what would have been
+/// accepted there (rendered via `CommandNode::hint`), so the client can
+/// render a usage hint.
+#[derive(Debug)]
+pub struct CommandError {
+    pub position: usize,
+    pub expected: Vec<String>,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.expected.is_empty() {
+            write!(f, "Unknown command.")
+        } else {
+            write!(
+                f,
+                "Unexpected input at word {}, expected one of: {}.",
+                self.position,
+                self.expected.join(", ")
+            )
+        }
+    }
+}
+
+/// Registry of top-level commands, keyed by their literal name.
+pub struct Commands {
+    roots: HashMap<String, CommandNode>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Commands {
+            roots: HashMap::new(),
+        }
+    }
+
+    /// Register (or fetch) a top-level command node, e.g.
+    /// `commands.create_cmd("give")`, so gameplay modules can build out
+    /// their own subtree without touching the dispatcher.
+    pub fn create_cmd(&mut self, name: &str) -> &mut CommandNode {
+        self.roots
+            .entry(name.to_owned())
+            .or_insert_with(|| CommandNode::new(name, ArgType::Literal))
+    }
+
+    /// Walks the full command tree, returning a usage string per
+    /// resolvable path (e.g. `give <item:String> <count:Int>`), returned
+    /// to each session in its `ConnectionResult` on connect.
+    pub fn list(&self) -> Vec<String> {
+        let mut usages = Vec::new();
+        for root in self.roots.values() {
+            root.collect_usages("", &mut usages);
+        }
+        usages
+    }
+
+    /// Tokenize on whitespace, match the first token against a registered
+    /// command, then walk the rest of the tree. Returns a `CommandError`
+    /// describing the expected node types when no path resolves.
+    pub fn dispatch(&self, id: usize, input: &str, world: &mut World) -> Result<(), CommandError> {
+        let tokens: Vec<&str> = input.trim().split_whitespace().collect();
+
+        let root = tokens.first().and_then(|name| self.roots.get(*name));
+
+        let root = match root {
+            Some(root) => root,
+            None => {
+                let mut expected: Vec<String> = self.roots.keys().cloned().collect();
+                expected.sort();
+
+                return Err(CommandError {
+                    position: 0,
+                    expected,
+                });
+            }
+        };
+
+        let mut args = Vec::new();
+        root.walk(id, &tokens[1..], &mut args, world, 1)
+    }
+}
+
+fn cmd_give(id: usize, args: &[ArgValue], world: &mut World) {
+    let (item, count) = match args {
+        [ArgValue::String(item), ArgValue::Int(count)] => (item.clone(), *count),
+        _ => return,
+    };
+
+    if let Some(recipient) = world.clients.get(&id) {
+        recipient
+            .do_send(Message(format!("Gave {} x{}", item, count)))
+            .unwrap();
+    }
+}
+
+fn cmd_tp(id: usize, args: &[ArgValue], world: &mut World) {
+    let (x, y, z) = match args {
+        [ArgValue::Vec3(x, y, z)] => (*x, *y, *z),
+        _ => return,
+    };
+
+    if let Some(recipient) = world.clients.get(&id) {
+        recipient
+            .do_send(Message(format!("Teleported to ({}, {}, {})", x, y, z)))
+            .unwrap();
+    }
+}
+
+fn cmd_time(id: usize, args: &[ArgValue], world: &mut World) {
+    let ticks = match args {
+        [ArgValue::Int(ticks)] => *ticks,
+        _ => return,
+    };
+
+    if let Some(recipient) = world.clients.get(&id) {
+        recipient
+            .do_send(Message(format!("Set time to {}", ticks)))
+            .unwrap();
+    }
+}
+
+/// Builds the default command tree. Future gameplay modules can register
+/// more commands the same way, via `Commands::create_cmd`.
+pub fn register_commands() -> Commands {
+    let mut commands = Commands::new();
+
+    commands
+        .create_cmd("give")
+        .add_child("item", ArgType::String)
+        .add_child("count", ArgType::Int)
+        .set_handler(cmd_give);
+
+    commands
+        .create_cmd("tp")
+        .add_child("position", ArgType::Vec3)
+        .set_handler(cmd_tp);
+
+    commands
+        .create_cmd("time")
+        .add_child("ticks", ArgType::Int)
+        .set_handler(cmd_time);
+
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_consume_literal_matches_only_its_own_name() {
+        let node = CommandNode::new("give", ArgType::Literal);
+
+        assert_eq!(
+            node.try_consume(&["give", "rest"]),
+            Some((ArgValue::Literal("give".to_owned()), &["rest"][..]))
+        );
+        assert_eq!(node.try_consume(&["tp"]), None);
+        assert_eq!(node.try_consume(&[]), None);
+    }
+
+    #[test]
+    fn try_consume_typed_args_parse_or_reject() {
+        let int_node = CommandNode::new("count", ArgType::Int);
+        assert_eq!(
+            int_node.try_consume(&["5"]),
+            Some((ArgValue::Int(5), &[][..]))
+        );
+        assert_eq!(int_node.try_consume(&["five"]), None);
+
+        let vec3_node = CommandNode::new("position", ArgType::Vec3);
+        assert_eq!(
+            vec3_node.try_consume(&["1", "2", "3", "rest"]),
+            Some((ArgValue::Vec3(1.0, 2.0, 3.0), &["rest"][..]))
+        );
+        assert_eq!(vec3_node.try_consume(&["1", "2"]), None);
+    }
+
+    #[test]
+    fn list_includes_full_usage_for_every_registered_command() {
+        let usages = register_commands().list();
+
+        assert!(usages.contains(&"give <item:String> <count:Int>".to_owned()));
+        assert!(usages.contains(&"tp <position:Vec3>".to_owned()));
+        assert!(usages.contains(&"time <ticks:Int>".to_owned()));
+    }
+
+    #[test]
+    fn command_error_display_names_candidates() {
+        let unknown = CommandError {
+            position: 0,
+            expected: vec!["give".to_owned(), "tp".to_owned()],
+        };
+        assert_eq!(
+            unknown.to_string(),
+            "Unexpected input at word 0, expected one of: give, tp."
+        );
+
+        let empty = CommandError {
+            position: 2,
+            expected: vec![],
+        };
+        assert_eq!(empty.to_string(), "Unknown command.");
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prost::Message as ProstMessage;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::libs::types::Coords2;
+
+use super::models::ChunkProtocol;
+
+const MAX_GOSSIP_ADDRS: usize = 32;
+const NODE_TIMEOUT: Duration = Duration::from_secs(120);
+const GOSSIP_FANOUT: usize = 3;
+// size, in chunks, of the square region a single node owns.
+const REGION_SIZE: i32 = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum GossipMessage {
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    GetOwnership,
+    Ownership(Vec<((i32, i32), SocketAddr)>),
+    ChunkForward {
+        world: String,
+        cx: i32,
+        cz: i32,
+        needs_voxels: bool,
+    },
+    ChunkForwardResult(Option<Vec<u8>>),
+    GenerateForward { world: String, coords: Vec<(i32, i32)> },
+}
+
+/// Known peer server addresses, sorted by recency so gossip always shares
+/// the freshest nodes and stale ones can be evicted.
+#[derive(Default)]
+pub struct NodeTable {
+    nodes: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl NodeTable {
+    pub fn new() -> Self {
+        NodeTable {
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, addr: SocketAddr) {
+        self.nodes.lock().unwrap().insert(addr, Instant::now());
+    }
+
+    /// The `limit` most-recently-seen nodes, for answering `GetAddr` and
+    /// for keeping gossip messages bounded.
+    pub fn freshest(&self, limit: usize) -> Vec<SocketAddr> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut entries: Vec<(SocketAddr, Instant)> = nodes.iter().map(|(a, t)| (*a, *t)).collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1));
+        entries.into_iter().take(limit).map(|(a, _)| a).collect()
+    }
+
+    /// A random subset of known nodes to re-request `addr` from, so the
+    /// table keeps healing itself without a central coordinator.
+    pub fn random_subset(&self, n: usize) -> Vec<SocketAddr> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut addrs: Vec<SocketAddr> = nodes.keys().copied().collect();
+        addrs.shuffle(&mut rand::thread_rng());
+        addrs.truncate(n);
+        addrs
+    }
+
+    pub fn evict_stale(&self) {
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.retain(|_, last_seen| last_seen.elapsed() < NODE_TIMEOUT);
+    }
+}
+
+/// Assigns contiguous `REGION_SIZE`-chunk regions to specific server
+/// nodes, so a `ChunkRequest`/`Generate` for coordinates outside this
+/// node's regions can be forwarded to whoever owns them.
+#[derive(Default)]
+pub struct OwnershipMap {
+    regions: Mutex<HashMap<(i32, i32), SocketAddr>>,
+}
+
+impl OwnershipMap {
+    pub fn new() -> Self {
+        OwnershipMap {
+            regions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn region_of(coords: &Coords2<i32>) -> (i32, i32) {
+        (
+            coords.0.div_euclid(REGION_SIZE),
+            coords.1.div_euclid(REGION_SIZE),
+        )
+    }
+
+    pub fn assign(&self, coords: &Coords2<i32>, owner: SocketAddr) {
+        self.regions
+            .lock()
+            .unwrap()
+            .insert(Self::region_of(coords), owner);
+    }
+
+    /// Same as `assign`, but keyed directly by region coordinates - what
+    /// `start_federation`'s static config and peer-gossiped ownership use.
+    pub fn assign_region(&self, region: (i32, i32), owner: SocketAddr) {
+        self.regions.lock().unwrap().insert(region, owner);
+    }
+
+    /// `None` means this node owns the region (the default).
+    pub fn owner_of(&self, coords: &Coords2<i32>) -> Option<SocketAddr> {
+        self.regions
+            .lock()
+            .unwrap()
+            .get(&Self::region_of(coords))
+            .copied()
+    }
+
+    /// Every region this node currently knows an owner for, gossiped to
+    /// peers so ownership assigned on one node's static config propagates
+    /// to the rest without needing to be repeated everywhere.
+    pub fn snapshot(&self) -> Vec<((i32, i32), SocketAddr)> {
+        self.regions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(region, owner)| (*region, *owner))
+            .collect()
+    }
+
+    /// Learn region ownership gossiped from a peer. Never overrides a
+    /// region this node already has an owner for - local static config
+    /// always wins over whatever's heard secondhand.
+    pub fn merge(&self, entries: Vec<((i32, i32), SocketAddr)>) {
+        let mut regions = self.regions.lock().unwrap();
+        for (region, owner) in entries {
+            regions.entry(region).or_insert(owner);
+        }
+    }
+}
+
+/// A chunk request received from a peer node, waiting for `WsServer` to
+/// resolve it against the `World` data only the actor has access to. The
+/// open connection is kept alive so the reply can be written once ready.
+pub struct PendingForward {
+    pub world: String,
+    pub coords: Coords2<i32>,
+    pub needs_voxels: bool,
+    reply: TcpStream,
+}
+
+fn coords_from_parts(cx: i32, cz: i32) -> Coords2<i32> {
+    Coords2(cx, cz)
+}
+
+/// A batch of chunk coordinates a peer wants generated here, because this
+/// node owns the region they fall in.
+pub struct PendingGenerate {
+    pub world: String,
+    pub coords: Vec<Coords2<i32>>,
+}
+
+/// Inter-server networking: a self-healing table of known peers plus an
+/// ownership map so multiple `WsServer` processes can shard one logical
+/// world and forward requests for regions they don't own.
+pub struct Federation {
+    pub self_addr: SocketAddr,
+    pub nodes: Arc<NodeTable>,
+    pub ownership: Arc<OwnershipMap>,
+    inbox: Arc<Mutex<VecDeque<PendingForward>>>,
+    generate_inbox: Arc<Mutex<VecDeque<PendingGenerate>>>,
+}
+
+impl Federation {
+    /// Bind the gossip listener and start the connection-handling thread.
+    /// `regions` is this node's statically-configured slice of ownership
+    /// (from `metadata/worlds.json`'s `federation.regions`); it's seeded
+    /// into the map up front and then gossiped to peers via `heal`, so a
+    /// region only needs to be declared on the node that owns it.
+    pub fn start(
+        self_addr: SocketAddr,
+        seeds: Vec<SocketAddr>,
+        regions: Vec<((i32, i32), SocketAddr)>,
+    ) -> Self {
+        let nodes = Arc::new(NodeTable::new());
+        let ownership = Arc::new(OwnershipMap::new());
+        let inbox = Arc::new(Mutex::new(VecDeque::new()));
+        let generate_inbox = Arc::new(Mutex::new(VecDeque::new()));
+
+        for seed in seeds {
+            nodes.record(seed);
+        }
+
+        for (region, owner) in regions {
+            ownership.assign_region(region, owner);
+        }
+
+        let listener = TcpListener::bind(self_addr).unwrap();
+        let accept_nodes = nodes.clone();
+        let accept_ownership = ownership.clone();
+        let accept_inbox = inbox.clone();
+        let accept_generate_inbox = generate_inbox.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let nodes = accept_nodes.clone();
+                let ownership = accept_ownership.clone();
+                let inbox = accept_inbox.clone();
+                let generate_inbox = accept_generate_inbox.clone();
+                thread::spawn(move || {
+                    handle_connection(stream, &nodes, &ownership, &inbox, &generate_inbox)
+                });
+            }
+        });
+
+        Federation {
+            self_addr,
+            nodes,
+            ownership,
+            inbox,
+            generate_inbox,
+        }
+    }
+
+    /// Drain chunk requests forwarded by peer nodes since the last tick,
+    /// for `WsServer` to resolve against its own `World` data.
+    pub fn take_pending(&self) -> Vec<PendingForward> {
+        self.inbox.lock().unwrap().drain(..).collect()
+    }
+
+    /// Drain generation batches forwarded by peer nodes since the last
+    /// tick, for `WsServer` to enqueue onto its local `ChunkPipeline`.
+    pub fn take_pending_generate(&self) -> Vec<PendingGenerate> {
+        self.generate_inbox.lock().unwrap().drain(..).collect()
+    }
+
+    /// Answer a forwarded request with the locally-resolved protocol (or
+    /// `None` if this node hasn't generated that chunk either).
+    pub fn reply_chunk(pending: PendingForward, protocol: Option<ChunkProtocol>) {
+        let PendingForward { mut reply, .. } = pending;
+        let bytes = protocol.map(|p| p.encode_to_vec());
+        let _ = send_message(&mut reply, &GossipMessage::ChunkForwardResult(bytes));
+    }
+
+    /// Re-request `addr` and region ownership from a random subset of
+    /// known nodes, and drop anything not seen within the timeout. Call
+    /// this on a slow interval; it's how ownership assigned on one node's
+    /// static config reaches the rest of the mesh.
+    pub fn heal(&self) {
+        self.nodes.evict_stale();
+
+        for addr in self.nodes.random_subset(GOSSIP_FANOUT) {
+            let nodes = self.nodes.clone();
+            let ownership = self.ownership.clone();
+
+            thread::spawn(move || {
+                if let Ok(learned) = request_addr(addr) {
+                    for learned_addr in learned {
+                        nodes.record(learned_addr);
+                    }
+                }
+
+                if let Ok(learned) = request_ownership(addr) {
+                    ownership.merge(learned);
+                }
+            });
+        }
+    }
+
+    /// Forward a chunk request to its owning node and relay the result
+    /// back. Blocks on a synchronous socket round trip, so callers must
+    /// run this off the actor thread (see `Handler<ChunkRequest>`).
+    pub fn forward_chunk_request(
+        owner: SocketAddr,
+        world: &str,
+        coords: Coords2<i32>,
+        needs_voxels: bool,
+    ) -> Option<ChunkProtocol> {
+        let stream = TcpStream::connect(owner).ok()?;
+        let mut reader = BufReader::new(stream.try_clone().ok()?);
+        let mut writer = stream;
+
+        send_message(
+            &mut writer,
+            &GossipMessage::ChunkForward {
+                world: world.to_owned(),
+                cx: coords.0,
+                cz: coords.1,
+                needs_voxels,
+            },
+        )
+        .ok()?;
+
+        let response: GossipMessage = recv_message(&mut reader).ok()?;
+
+        match response {
+            GossipMessage::ChunkForwardResult(Some(bytes)) => ChunkProtocol::decode(&bytes[..]).ok(),
+            _ => None,
+        }
+    }
+
+    /// Tell the owning node to generate a batch of coordinates. Fire and
+    /// forget - `Generate`'s own result is `()`, so there's nothing to
+    /// relay back, and the owning node's normal chunk drain picks up the
+    /// result for whichever of its own clients end up near there.
+    pub fn forward_generate(&self, owner: SocketAddr, world: &str, coords: Vec<Coords2<i32>>) {
+        let world = world.to_owned();
+
+        thread::spawn(move || {
+            let mut stream = match TcpStream::connect(owner) {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+
+            let _ = send_message(
+                &mut stream,
+                &GossipMessage::GenerateForward {
+                    world,
+                    coords: coords.into_iter().map(|c| (c.0, c.1)).collect(),
+                },
+            );
+        });
+    }
+}
+
+fn send_message(stream: &mut TcpStream, message: &GossipMessage) -> std::io::Result<()> {
+    let json = serde_json::to_string(message)?;
+    writeln!(stream, "{}", json)
+}
+
+fn recv_message(reader: &mut BufReader<TcpStream>) -> std::io::Result<GossipMessage> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn request_addr(addr: SocketAddr) -> std::io::Result<Vec<SocketAddr>> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    send_message(&mut stream, &GossipMessage::GetAddr)?;
+
+    match recv_message(&mut reader)? {
+        GossipMessage::Addr(nodes) => Ok(nodes),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn request_ownership(addr: SocketAddr) -> std::io::Result<Vec<((i32, i32), SocketAddr)>> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    send_message(&mut stream, &GossipMessage::GetOwnership)?;
+
+    match recv_message(&mut reader)? {
+        GossipMessage::Ownership(regions) => Ok(regions),
+        _ => Ok(Vec::new()),
+    }
+}
+
+// handles a single incoming gossip/forward connection: answer `GetAddr`
+// with our freshest nodes, answer `GetOwnership` with our known region
+// assignments, record whoever connected to us, and queue forwarded chunk
+// and generation requests for `WsServer` to resolve on its own thread.
+fn handle_connection(
+    stream: TcpStream,
+    nodes: &Arc<NodeTable>,
+    ownership: &Arc<OwnershipMap>,
+    inbox: &Arc<Mutex<VecDeque<PendingForward>>>,
+    generate_inbox: &Arc<Mutex<VecDeque<PendingGenerate>>>,
+) {
+    if let Ok(addr) = stream.peer_addr() {
+        nodes.record(addr);
+    }
+
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    });
+
+    let message = match recv_message(&mut reader) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+
+    match message {
+        GossipMessage::GetAddr => {
+            let mut writer = stream;
+            let _ = send_message(
+                &mut writer,
+                &GossipMessage::Addr(nodes.freshest(MAX_GOSSIP_ADDRS)),
+            );
+        }
+        GossipMessage::Addr(learned) => {
+            for addr in learned {
+                nodes.record(addr);
+            }
+        }
+        GossipMessage::GetOwnership => {
+            let mut writer = stream;
+            let _ = send_message(&mut writer, &GossipMessage::Ownership(ownership.snapshot()));
+        }
+        GossipMessage::Ownership(learned) => {
+            ownership.merge(learned);
+        }
+        GossipMessage::ChunkForward {
+            world,
+            cx,
+            cz,
+            needs_voxels,
+        } => {
+            inbox.lock().unwrap().push_back(PendingForward {
+                world,
+                coords: coords_from_parts(cx, cz),
+                needs_voxels,
+                reply: stream,
+            });
+        }
+        GossipMessage::ChunkForwardResult(_) => {}
+        GossipMessage::GenerateForward { world, coords } => {
+            generate_inbox.lock().unwrap().push_back(PendingGenerate {
+                world,
+                coords: coords
+                    .into_iter()
+                    .map(|(cx, cz)| coords_from_parts(cx, cz))
+                    .collect(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn node_table_freshest_orders_most_recent_first() {
+        let table = NodeTable::new();
+
+        table.record(addr(1));
+        table.record(addr(2));
+        table.record(addr(3));
+
+        assert_eq!(table.freshest(2), vec![addr(3), addr(2)]);
+    }
+
+    #[test]
+    fn node_table_evict_stale_keeps_fresh_nodes() {
+        let table = NodeTable::new();
+        table.record(addr(1));
+
+        // nothing has timed out yet
+        table.evict_stale();
+        assert_eq!(table.freshest(10), vec![addr(1)]);
+    }
+
+    #[test]
+    fn ownership_map_owner_of_is_none_until_assigned() {
+        let map = OwnershipMap::new();
+        let coords = Coords2(0, 0);
+
+        assert_eq!(map.owner_of(&coords), None);
+
+        map.assign(&coords, addr(1));
+        assert_eq!(map.owner_of(&coords), Some(addr(1)));
+    }
+
+    #[test]
+    fn ownership_map_groups_coords_by_region() {
+        let map = OwnershipMap::new();
+
+        map.assign(&Coords2(0, 0), addr(1));
+
+        // within the same REGION_SIZE block as (0, 0)
+        assert_eq!(map.owner_of(&Coords2(REGION_SIZE - 1, REGION_SIZE - 1)), Some(addr(1)));
+        // one region over
+        assert_eq!(map.owner_of(&Coords2(REGION_SIZE, 0)), None);
+    }
+
+    #[test]
+    fn ownership_map_merge_does_not_override_local_assignment() {
+        let map = OwnershipMap::new();
+        map.assign_region((0, 0), addr(1));
+
+        map.merge(vec![((0, 0), addr(2)), ((1, 0), addr(2))]);
+
+        assert_eq!(map.owner_of(&Coords2(0, 0)), Some(addr(1)));
+        assert_eq!(map.owner_of(&Coords2(REGION_SIZE, 0)), Some(addr(2)));
+    }
+}
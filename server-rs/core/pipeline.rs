@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::libs::types::Coords2;
+
+use super::registry::Registry;
+use super::world::{Chunk, ChunksConfig, World};
+
+const WORKER_COUNT: usize = 4;
+const MAX_IN_FLIGHT: usize = 256;
+
+// the immutable inputs a worker needs to build a chunk - no access to the
+// live `World` the actor owns, just what it takes to regenerate one.
+#[derive(Clone)]
+struct GenerationContext {
+    config: ChunksConfig,
+    registry: Registry,
+}
+
+struct WorkItem {
+    world: String,
+    coords: Coords2<i32>,
+}
+
+pub struct GeneratedChunk {
+    pub world: String,
+    pub coords: Coords2<i32>,
+    pub chunk: Chunk,
+}
+
+struct Shared {
+    contexts: HashMap<String, GenerationContext>,
+    queue: Mutex<VecDeque<WorkItem>>,
+    condvar: Condvar,
+    shutdown: AtomicBool,
+}
+
+/// Background chunk generation, modeled on a verification worker pool: a
+/// fixed set of OS threads pull coordinates off a shared, mutex+condvar
+/// guarded queue, build the chunk off the actor thread, and push the
+/// result onto an output channel the actor drains on its own interval.
+/// This keeps a single slow `Generate` request from stalling every other
+/// connected client.
+pub struct ChunkPipeline {
+    shared: Arc<Shared>,
+    queued: Mutex<HashSet<(String, Coords2<i32>)>>,
+    in_flight: Arc<Mutex<usize>>,
+    output_rx: Receiver<GeneratedChunk>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ChunkPipeline {
+    /// Snapshot each world's generation inputs once, up front, so workers
+    /// never need to touch the actor-owned `World` values.
+    pub fn new(worlds: &HashMap<String, World>) -> Self {
+        let contexts = worlds
+            .iter()
+            .map(|(name, world)| {
+                (
+                    name.to_owned(),
+                    GenerationContext {
+                        config: world.chunks.config.clone(),
+                        registry: world.chunks.registry.clone(),
+                    },
+                )
+            })
+            .collect();
+
+        let shared = Arc::new(Shared {
+            contexts,
+            queue: Mutex::new(VecDeque::new()),
+            condvar: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let (output_tx, output_rx) = mpsc::channel();
+        let in_flight = Arc::new(Mutex::new(0usize));
+
+        let workers = (0..WORKER_COUNT)
+            .map(|_| Self::spawn_worker(shared.clone(), in_flight.clone(), output_tx.clone()))
+            .collect();
+
+        ChunkPipeline {
+            shared,
+            queued: Mutex::new(HashSet::new()),
+            in_flight,
+            output_rx,
+            workers,
+        }
+    }
+
+    fn spawn_worker(
+        shared: Arc<Shared>,
+        in_flight: Arc<Mutex<usize>>,
+        output_tx: Sender<GeneratedChunk>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            let item = {
+                let mut queue = shared.queue.lock().unwrap();
+                loop {
+                    if shared.shutdown.load(Ordering::Relaxed) {
+                        return;
+                    }
+
+                    if let Some(item) = queue.pop_front() {
+                        break item;
+                    }
+
+                    queue = shared.condvar.wait(queue).unwrap();
+                }
+            };
+
+            let context = match shared.contexts.get(&item.world) {
+                Some(context) => context,
+                None => {
+                    *in_flight.lock().unwrap() -= 1;
+                    continue;
+                }
+            };
+
+            let chunk = World::build_chunk(item.coords.clone(), &context.config, &context.registry);
+
+            *in_flight.lock().unwrap() -= 1;
+
+            let sent = output_tx.send(GeneratedChunk {
+                world: item.world,
+                coords: item.coords,
+                chunk,
+            });
+
+            if sent.is_err() {
+                return;
+            }
+        })
+    }
+
+    /// Enqueue coordinates for a world, skipping anything already queued
+    /// or mid-flight and backing off once `MAX_IN_FLIGHT` is reached.
+    pub fn enqueue(&self, world: &str, coords: impl IntoIterator<Item = Coords2<i32>>) {
+        let mut queued = self.queued.lock().unwrap();
+        let mut queue = self.shared.queue.lock().unwrap();
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        for coords in coords {
+            let key = (world.to_owned(), coords.clone());
+
+            if *in_flight >= MAX_IN_FLIGHT || queued.contains(&key) {
+                continue;
+            }
+
+            queued.insert(key);
+            *in_flight += 1;
+            queue.push_back(WorkItem {
+                world: world.to_owned(),
+                coords,
+            });
+        }
+
+        self.shared.condvar.notify_all();
+    }
+
+    /// Drain every chunk finished since the last tick.
+    pub fn drain(&self) -> Vec<GeneratedChunk> {
+        let chunks: Vec<GeneratedChunk> = self.output_rx.try_iter().collect();
+
+        if !chunks.is_empty() {
+            let mut queued = self.queued.lock().unwrap();
+            for chunk in &chunks {
+                queued.remove(&(chunk.world.clone(), chunk.coords.clone()));
+            }
+        }
+
+        chunks
+    }
+
+    /// Signal every worker to exit, used on server shutdown; `join` then
+    /// waits for them. The flag is set under the queue lock so a worker
+    /// between its flag check and `wait` cannot miss the wakeup.
+    pub fn shutdown(&self) {
+        let _queue = self.shared.queue.lock().unwrap();
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        self.shared.condvar.notify_all();
+    }
+
+    pub fn join(self) {
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
@@ -3,7 +3,8 @@ use rand::{self, rngs::ThreadRng, Rng};
 use actix::prelude::*;
 use actix_web_actors::ws;
 
-use std::collections::{HashMap, VecDeque};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
 use std::thread::current;
 use std::time::{Duration, Instant};
@@ -17,13 +18,18 @@ use crate::models::{
 use crate::utils::convert::{map_voxel_to_chunk, map_world_to_voxel};
 use crate::utils::json;
 
+use super::commands::{register_commands, Commands};
+use super::federation::Federation;
 use super::models::ChunkProtocol;
+use super::pipeline::ChunkPipeline;
 use super::registry::Registry;
 use super::world::WorldMetrics;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CHUNKING_INTERVAL: Duration = Duration::from_millis(16);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const PIPELINE_DRAIN_INTERVAL: Duration = Duration::from_millis(16);
+const FEDERATION_HEAL_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -33,6 +39,7 @@ pub struct Message(pub String);
 pub struct ConnectionResult {
     pub id: usize,
     pub metrics: WorldMetrics,
+    pub commands: Vec<String>,
 }
 
 #[derive(Message)]
@@ -80,6 +87,77 @@ pub struct ClientMessage {
     pub world: String,
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerUpdate {
+    pub id: usize,
+    pub world: String,
+    pub name: Option<String>,
+    pub position: Coords3<f32>,
+    pub rotation: Quaternion,
+    pub current_chunk: Coords2<i32>,
+    pub render_radius: i16,
+}
+
+// last-known transform of a connected peer, kept on the server so area-of-
+// interest broadcasts don't need to ask every session for its state.
+#[derive(Debug, Clone)]
+struct PeerState {
+    world: String,
+    name: Option<String>,
+    position: Coords3<f32>,
+    rotation: Quaternion,
+    current_chunk: Coords2<i32>,
+}
+
+// squared chunk-space distance, used both for area-of-interest filtering
+// and for prioritizing chunk requests.
+fn chunk_dist_sq(a: &Coords2<i32>, b: &Coords2<i32>) -> i64 {
+    let dx = (a.0 - b.0) as i64;
+    let dz = (a.1 - b.1) as i64;
+    dx * dx + dz * dz
+}
+
+// every chunk coordinate within `radius` chunks of `center`, for enqueuing
+// a ring of generation work around a player.
+fn chunk_ring(center: &Coords2<i32>, radius: i16) -> Vec<Coords2<i32>> {
+    let radius = radius as i32;
+    let radius_sq = (radius * radius) as i64;
+    let mut ring = Vec::new();
+
+    for dx in -radius..=radius {
+        for dz in -radius..=radius {
+            let coords = Coords2(center.0 + dx, center.1 + dz);
+            if chunk_dist_sq(center, &coords) <= radius_sq {
+                ring.push(coords);
+            }
+        }
+    }
+
+    ring
+}
+
+// a chunk request waiting to be sent, ordered so `BinaryHeap` (a max-heap)
+// pops the nearest chunk first: `priority` is the *negative* squared
+// distance, so the smallest distance sorts highest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PendingChunk {
+    coords: Coords2<i32>,
+    priority: i64,
+}
+
+impl Ord for PendingChunk {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+    }
+}
+
+impl PartialOrd for PendingChunk {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 // list of available rooms
 pub struct ListWorlds;
 
@@ -87,11 +165,16 @@ impl actix::Message for ListWorlds {
     type Result = Vec<String>;
 }
 
-#[derive(Debug)]
 pub struct WsServer {
     clients: HashMap<usize, Recipient<Message>>,
     worlds: HashMap<String, World>,
     rng: ThreadRng,
+    commands: Commands,
+    peers: HashMap<usize, PeerState>,
+    // `None` only after `stopped` has taken it to join its worker threads.
+    pipeline: Option<ChunkPipeline>,
+    // `None` when this process isn't configured to federate with peers.
+    federation: Option<Federation>,
 }
 
 impl WsServer {
@@ -114,13 +197,65 @@ impl WsServer {
             worlds.insert(new_world.name.to_owned(), new_world);
         }
 
+        let pipeline = ChunkPipeline::new(&worlds);
+        let federation = Self::start_federation(&worlds_json);
+
         WsServer {
             worlds,
             clients: HashMap::new(),
             rng: rand::thread_rng(),
+            commands: register_commands(),
+            peers: HashMap::new(),
+            pipeline: Some(pipeline),
+            federation,
         }
     }
 
+    // `metadata/worlds.json` may carry an optional `federation` section
+    // (`{"bind_addr": "...", "seeds": [...], "regions": [...]}`) describing
+    // how this node joins the gossip network; servers that omit it run
+    // standalone. `regions` is this node's own slice of ownership, e.g.
+    // `[{"region": [0, 0], "owner": "10.0.0.1:9000"}]` - each entry only
+    // needs to be declared on the node that owns it, since `Federation`
+    // gossips known ownership to the rest of the mesh.
+    fn start_federation(worlds_json: &serde_json::Value) -> Option<Federation> {
+        let bind_addr = worlds_json["federation"]["bind_addr"].as_str()?.parse().ok()?;
+
+        let seeds = worlds_json["federation"]["seeds"]
+            .as_array()
+            .map(|seeds| {
+                seeds
+                    .iter()
+                    .filter_map(|s| s.as_str()?.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let regions = worlds_json["federation"]["regions"]
+            .as_array()
+            .map(|regions| {
+                regions
+                    .iter()
+                    .filter_map(|entry| {
+                        let region = entry["region"].as_array()?;
+                        let rx = region.first()?.as_i64()? as i32;
+                        let rz = region.get(1)?.as_i64()? as i32;
+                        let owner = entry["owner"].as_str()?.parse().ok()?;
+                        Some(((rx, rz), owner))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Federation::start(bind_addr, seeds, regions))
+    }
+
+    // `pipeline` is only `None` after shutdown has taken it, which never
+    // happens while the actor is still handling messages.
+    fn pipeline(&self) -> &ChunkPipeline {
+        self.pipeline.as_ref().unwrap()
+    }
+
     pub fn send_message(&self, world: &str, message: &str, skip_id: usize) {
         if let Some(world) = self.worlds.get(world) {
             for (id, recipient) in &world.clients {
@@ -134,17 +269,74 @@ impl WsServer {
 
 impl Actor for WsServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(PIPELINE_DRAIN_INTERVAL, |act, _| {
+            for generated in act.pipeline().drain() {
+                if let Some(world) = act.worlds.get_mut(&generated.world) {
+                    world.chunks.insert(generated.coords, generated.chunk);
+                }
+            }
+        });
+
+        ctx.run_interval(FEDERATION_HEAL_INTERVAL, |act, _| {
+            if let Some(federation) = &act.federation {
+                federation.heal();
+            }
+        });
+
+        ctx.run_interval(PIPELINE_DRAIN_INTERVAL, |act, _| {
+            let pending = match &act.federation {
+                Some(federation) => federation.take_pending(),
+                None => return,
+            };
+
+            for forward in pending {
+                let protocol = act
+                    .worlds
+                    .get_mut(&forward.world)
+                    .and_then(|world| world.chunks.get(&forward.coords))
+                    .map(|chunk| chunk.get_protocol(forward.needs_voxels));
+
+                Federation::reply_chunk(forward, protocol);
+            }
+        });
+
+        ctx.run_interval(PIPELINE_DRAIN_INTERVAL, |act, _| {
+            let pending = match &act.federation {
+                Some(federation) => federation.take_pending_generate(),
+                None => return,
+            };
+
+            for forward in pending {
+                let coords: Vec<Coords2<i32>> = match act.worlds.get(&forward.world) {
+                    Some(w) => forward
+                        .coords
+                        .into_iter()
+                        .filter(|c| w.chunks.get(c).is_none())
+                        .collect(),
+                    None => continue,
+                };
+
+                act.pipeline().enqueue(&forward.world, coords);
+            }
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        // signal the worker threads to exit, then wait for them - a bare
+        // signal isn't "exiting cleanly", it's just hoping they do.
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline.shutdown();
+            pipeline.join();
+        }
+    }
 }
 
 impl Handler<Connect> for WsServer {
     type Result = MessageResult<Connect>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
-
-        // TODO: send join message here.
-        self.send_message(&"Main".to_owned(), "Someone joined", 0);
-
         // register session with random id
         let id = self.rng.gen::<usize>();
         self.clients.insert(id, msg.addr.clone()); // ? NOT SURE IF THIS WORKS
@@ -152,10 +344,15 @@ impl Handler<Connect> for WsServer {
         let world_name = msg.world_name;
         let world = self.worlds.get_mut(&world_name).unwrap();
         world.add_client(id, msg.addr.to_owned());
+        let metrics = world.chunks.metrics.clone();
+
+        let join_payload = serde_json::json!({ "type": "join", "id": id }).to_string();
+        self.send_message(&world_name, &join_payload, id);
 
         MessageResult(ConnectionResult {
             id,
-            metrics: world.chunks.metrics.clone(),
+            metrics,
+            commands: self.commands.list(),
         })
     }
 }
@@ -184,13 +381,47 @@ impl Handler<Generate> for WsServer {
             world,
         } = data;
 
-        let world = self.worlds.get_mut(&world).unwrap();
-        world.chunks.generate(coords, render_radius);
+        let ring = chunk_ring(&coords, render_radius);
+
+        // coordinates another node owns are forwarded there instead of
+        // generated locally; group by owner so each remote node gets one
+        // batched request instead of one connection per coordinate.
+        let ring: Vec<Coords2<i32>> = if let Some(federation) = &self.federation {
+            let mut local = Vec::new();
+            let mut remote: HashMap<std::net::SocketAddr, Vec<Coords2<i32>>> = HashMap::new();
+
+            for c in ring {
+                match federation.ownership.owner_of(&c) {
+                    Some(owner) if owner != federation.self_addr => {
+                        remote.entry(owner).or_default().push(c);
+                    }
+                    _ => local.push(c),
+                }
+            }
+
+            for (owner, coords) in remote {
+                federation.forward_generate(owner, &world, coords);
+            }
+
+            local
+        } else {
+            ring
+        };
+
+        let pending: Vec<Coords2<i32>> = match self.worlds.get(&world) {
+            Some(w) => ring
+                .into_iter()
+                .filter(|c| w.chunks.get(c).is_none())
+                .collect(),
+            None => return,
+        };
+
+        self.pipeline().enqueue(&world, pending);
     }
 }
 
 impl Handler<ChunkRequest> for WsServer {
-    type Result = MessageResult<ChunkRequest>;
+    type Result = ResponseFuture<ChunkRequestResult>;
 
     fn handle(&mut self, request: ChunkRequest, _: &mut Context<Self>) -> Self::Result {
         let ChunkRequest {
@@ -199,20 +430,61 @@ impl Handler<ChunkRequest> for WsServer {
             needs_voxels,
         } = request;
 
-        let world = self.worlds.get_mut(&world).unwrap();
+        if let Some(federation) = &self.federation {
+            if let Some(owner) = federation.ownership.owner_of(&coords) {
+                if owner != federation.self_addr {
+                    // the forward is a blocking socket round trip to another
+                    // process - run it off the actor thread so it can't stall
+                    // every other client connected to this node.
+                    return Box::pin(async move {
+                        let protocol = actix::rt::task::spawn_blocking(move || {
+                            Federation::forward_chunk_request(owner, &world, coords, needs_voxels)
+                        })
+                        .await
+                        .unwrap_or(None);
+
+                        ChunkRequestResult { protocol }
+                    });
+                }
+            }
+        }
 
+        let world = self.worlds.get_mut(&world).unwrap();
         let chunk = world.chunks.get(&coords);
 
-        if chunk.is_none() {
-            return MessageResult(ChunkRequestResult { protocol: None });
-        }
+        // TODO: OPTIMIZE THIS? CLONE?
+        let protocol = chunk.map(|chunk| chunk.get_protocol(needs_voxels));
 
-        let chunk = chunk.unwrap();
+        Box::pin(async move { ChunkRequestResult { protocol } })
+    }
+}
 
-        // TODO: OPTIMIZE THIS? CLONE?
-        MessageResult(ChunkRequestResult {
-            protocol: Some(chunk.get_protocol(needs_voxels)),
-        })
+impl Handler<ClientMessage> for WsServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
+        let ClientMessage { id, msg, world } = msg;
+
+        let json = match msg.parse_json() {
+            Ok(json) => json,
+            Err(_) => return,
+        };
+
+        let command = match json["text"].as_str().and_then(|text| text.strip_prefix('/')) {
+            Some(command) => command,
+            None => return,
+        };
+
+        let world = match self.worlds.get_mut(&world) {
+            Some(world) => world,
+            None => return,
+        };
+
+        if let Err(err) = self.commands.dispatch(id, command, world) {
+            if let Some(recipient) = self.clients.get(&id) {
+                recipient.do_send(Message(err.to_string())).unwrap();
+            }
+        }
     }
 }
 
@@ -233,8 +505,77 @@ impl Handler<Disconnect> for WsServer {
             }
         }
 
+        let peer = self.peers.remove(&msg.id);
+
+        let leave_payload = serde_json::json!({
+            "type": "leave",
+            "id": msg.id,
+            "name": peer.as_ref().and_then(|p| p.name.clone()),
+            "position": peer.as_ref().map(|p| (p.position.0, p.position.1, p.position.2)),
+        })
+        .to_string();
+
         for world in worlds {
-            self.send_message(&world, "Someone disconnected", 0)
+            self.send_message(&world, &leave_payload, 0)
+        }
+    }
+}
+
+impl Handler<PeerUpdate> for WsServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerUpdate, _: &mut Context<Self>) {
+        let PeerUpdate {
+            id,
+            world,
+            name,
+            position,
+            rotation,
+            current_chunk,
+            render_radius,
+        } = msg;
+
+        self.peers.insert(
+            id,
+            PeerState {
+                world: world.clone(),
+                name: name.clone(),
+                position: position.clone(),
+                rotation: rotation.clone(),
+                current_chunk: current_chunk.clone(),
+            },
+        );
+
+        let world = match self.worlds.get(&world) {
+            Some(world) => world,
+            None => return,
+        };
+
+        let payload = serde_json::json!({
+            "type": "peer",
+            "id": id,
+            "name": name,
+            "position": (position.0, position.1, position.2),
+            "rotation": (rotation.0, rotation.1, rotation.2, rotation.3),
+        })
+        .to_string();
+
+        let render_radius_sq = (render_radius as i64) * (render_radius as i64);
+
+        for (peer_id, recipient) in &world.clients {
+            if *peer_id == id {
+                continue;
+            }
+
+            let in_range = self
+                .peers
+                .get(peer_id)
+                .map(|peer| chunk_dist_sq(&peer.current_chunk, &current_chunk) <= render_radius_sq)
+                .unwrap_or(false);
+
+            if in_range {
+                recipient.do_send(Message(payload.clone())).unwrap();
+            }
         }
     }
 }
@@ -250,6 +591,8 @@ pub struct WsSession {
     pub world_name: String,
     // world metrics
     pub metrics: Option<WorldMetrics>,
+    // commands available on the server, sent once on connect
+    pub available_commands: Vec<String>,
     // name in world
     pub name: Option<String>,
     // chat server
@@ -260,8 +603,8 @@ pub struct WsSession {
     pub rotation: Quaternion,
     // current chunk in world
     pub current_chunk: Option<Coords2<i32>>,
-    // requested chunk in world
-    pub requested_chunks: VecDeque<Coords2<i32>>,
+    // requested chunks in world, nearest-first
+    pub requested_chunks: BinaryHeap<PendingChunk>,
     // radius of render?
     pub render_radius: i16,
 }
@@ -284,6 +627,7 @@ impl Actor for WsSession {
                     Ok(res) => {
                         act.id = res.id;
                         act.metrics = Some(res.metrics);
+                        act.available_commands = res.commands;
                     }
                     _ => ctx.stop(),
                 }
@@ -361,9 +705,9 @@ impl WsSession {
 
     fn chunk(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(CHUNKING_INTERVAL, |act, ctx| {
-            let requested_chunk = act.requested_chunks.pop_front();
+            let pending = act.requested_chunks.pop();
 
-            if let Some(coords) = requested_chunk {
+            if let Some(PendingChunk { coords, .. }) = pending {
                 act.addr
                     .send(ChunkRequest {
                         needs_voxels: true,
@@ -371,11 +715,25 @@ impl WsSession {
                         world: act.world_name.to_owned(),
                     })
                     .into_actor(act)
-                    .then(|res, act, ctx| {
+                    .then(move |res, act, ctx| {
                         match res {
                             Ok(ChunkRequestResult { protocol }) => {
                                 if protocol.is_none() {
-                                    act.requested_chunks.push_back(coords);
+                                    let render_radius_sq =
+                                        (act.render_radius as i64) * (act.render_radius as i64);
+                                    let dist = act
+                                        .current_chunk
+                                        .as_ref()
+                                        .map_or(0, |c| chunk_dist_sq(c, &coords));
+
+                                    // the chunk may have moved out of view while it
+                                    // was still generating - don't keep chasing it.
+                                    if act.current_chunk.is_none() || dist <= render_radius_sq {
+                                        act.requested_chunks.push(PendingChunk {
+                                            coords,
+                                            priority: -dist,
+                                        });
+                                    }
                                 } else {
                                     let protocol = protocol.unwrap();
 
@@ -400,8 +758,14 @@ impl WsSession {
 
                 let cx = json["x"].as_i64().unwrap() as i32;
                 let cz = json["z"].as_i64().unwrap() as i32;
+                let coords = Coords2(cx, cz);
+
+                let priority = self
+                    .current_chunk
+                    .as_ref()
+                    .map_or(0, |c| -chunk_dist_sq(c, &coords));
 
-                self.requested_chunks.push_back(Coords2(cx, cz));
+                self.requested_chunks.push(PendingChunk { coords, priority });
             }
             MessageType::Config => {}
             MessageType::Update => {}
@@ -418,11 +782,6 @@ impl WsSession {
                     ..
                 } = &message.peers[0];
 
-                // means this player just joined.
-                if self.name.is_none() {
-                    // TODO: broadcast "joined the game" message
-                }
-
                 self.name = Some(name.to_owned());
                 self.position = Coords3(*px, *py, *pz);
                 self.rotation = Quaternion(*qx, *qy, *qz, *qw);
@@ -444,14 +803,52 @@ impl WsSession {
                     || current_chunk.unwrap().1 != new_chunk.1
                 {
                     self.current_chunk = Some(new_chunk.clone());
+
+                    let render_radius_sq =
+                        (self.render_radius as i64) * (self.render_radius as i64);
+
+                    self.requested_chunks = self
+                        .requested_chunks
+                        .drain()
+                        .filter_map(|pending| {
+                            let dist = chunk_dist_sq(&new_chunk, &pending.coords);
+                            if dist > render_radius_sq {
+                                None
+                            } else {
+                                Some(PendingChunk {
+                                    coords: pending.coords,
+                                    priority: -dist,
+                                })
+                            }
+                        })
+                        .collect();
+
                     self.addr.do_send(Generate {
                         coords: new_chunk,
                         render_radius: self.render_radius,
                         world: self.world_name.to_owned(),
                     });
                 }
+
+                // relay this player's transform to everyone else in range;
+                // the server filters by area of interest on the other end.
+                self.addr.do_send(PeerUpdate {
+                    id: self.id,
+                    world: self.world_name.to_owned(),
+                    name: self.name.clone(),
+                    position: self.position.clone(),
+                    rotation: self.rotation.clone(),
+                    current_chunk: self.current_chunk.clone().unwrap(),
+                    render_radius: self.render_radius,
+                });
+            }
+            MessageType::Message => {
+                self.addr.do_send(ClientMessage {
+                    id: self.id,
+                    msg: message,
+                    world: self.world_name.to_owned(),
+                });
             }
-            MessageType::Message => {}
             MessageType::Init => {
                 println!("INIT?")
             }
@@ -467,3 +864,55 @@ impl WsSession {
         // }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_dist_sq_is_symmetric_and_zero_for_same_chunk() {
+        let a = Coords2(3, -2);
+        let b = Coords2(-1, 5);
+
+        assert_eq!(chunk_dist_sq(&a, &a), 0);
+        assert_eq!(chunk_dist_sq(&a, &b), chunk_dist_sq(&b, &a));
+        assert_eq!(chunk_dist_sq(&a, &b), (3 - -1i64).pow(2) + (-2 - 5i64).pow(2));
+    }
+
+    #[test]
+    fn chunk_ring_only_includes_coords_within_radius() {
+        let center = Coords2(0, 0);
+        let ring = chunk_ring(&center, 2);
+
+        assert!(ring.contains(&Coords2(0, 0)));
+        assert!(ring.contains(&Coords2(2, 0)));
+        assert!(ring.contains(&Coords2(1, 1)));
+        assert!(!ring.contains(&Coords2(2, 2)));
+
+        for coords in &ring {
+            assert!(chunk_dist_sq(&center, coords) <= 4);
+        }
+    }
+
+    #[test]
+    fn pending_chunk_heap_pops_nearest_first() {
+        let mut heap = BinaryHeap::new();
+
+        heap.push(PendingChunk {
+            coords: Coords2(10, 10),
+            priority: -200,
+        });
+        heap.push(PendingChunk {
+            coords: Coords2(1, 0),
+            priority: -1,
+        });
+        heap.push(PendingChunk {
+            coords: Coords2(5, 0),
+            priority: -25,
+        });
+
+        assert_eq!(heap.pop().unwrap().coords, Coords2(1, 0));
+        assert_eq!(heap.pop().unwrap().coords, Coords2(5, 0));
+        assert_eq!(heap.pop().unwrap().coords, Coords2(10, 10));
+    }
+}
@@ -0,0 +1,12 @@
+use specs::{Component, VecStorage};
+
+/// Half-angle, in radians, of an entity's forward-facing view cone.
+/// `ObserveSystem` rejects kd-tree candidates whose direction from the
+/// entity exceeds this angle from its facing direction, so targets
+/// directly behind or to the side aren't seen.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldOfView(pub f32);
+
+impl Component for FieldOfView {
+    type Storage = VecStorage<Self>;
+}
@@ -6,6 +6,7 @@ use server_common::{math::approx_equals, vec::Vec3};
 
 use crate::{
     comp::{
+        field_of_view::FieldOfView,
         lookat::{LookAt, LookTarget},
         rigidbody::RigidBody,
         view_radius::ViewRadius,
@@ -13,6 +14,15 @@ use crate::{
     engine::{chunks::Chunks, kdtree::KdTree},
 };
 
+// whether `dir` (the direction toward a candidate target, of length `dist`)
+// falls within `half_angle` radians of `forward`. Pulled out of `run` so
+// the angle math can be tested without the kd-tree/raycast machinery.
+fn within_fov(dir: &Vec3<f32>, forward: &Vec3<f32>, dist: f32, half_angle: f32) -> bool {
+    let cos_angle = dir.dot(forward) / dist;
+    let angle = cos_angle.clamp(-1.0, 1.0).acos();
+    angle <= half_angle
+}
+
 pub struct ObserveSystem;
 
 impl<'a> System<'a> for ObserveSystem {
@@ -22,19 +32,21 @@ impl<'a> System<'a> for ObserveSystem {
         ReadExpect<'a, Chunks>,
         ReadStorage<'a, RigidBody>,
         ReadStorage<'a, ViewRadius>,
+        ReadStorage<'a, FieldOfView>,
         WriteStorage<'a, LookAt>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
         use specs::Join;
 
-        let (tree, chunks, bodies, radiuses, mut look_ats) = data;
+        let (tree, chunks, bodies, radiuses, fovs, mut look_ats) = data;
 
         let dimension = chunks.config.dimension;
         let test_solid = |x: i32, y: i32, z: i32| -> bool { chunks.get_solidity_by_voxel(x, y, z) };
 
-        for (body, radius, look_at) in (&bodies, &radiuses, &mut look_ats).join() {
+        for (body, radius, fov, look_at) in (&bodies, &radiuses, fovs.maybe(), &mut look_ats).join() {
             let mut position = body.get_head_position();
+            let forward = body.get_forward_direction();
 
             // loop until found or nothing found
             let mut closest: Option<Vec3<f32>> = None;
@@ -73,6 +85,17 @@ impl<'a> System<'a> for ObserveSystem {
                         break;
                     }
 
+                    // outside this entity's field of view - behind/flanking,
+                    // widen the search instead of accepting it blindly
+                    if let Some(fov) = fov {
+                        if !approx_equals(&dist, &0.0) && !within_fov(&dir, &forward, dist, fov.0) {
+                            offset += 1;
+                            count += 1;
+                            closest = None;
+                            continue;
+                        }
+                    }
+
                     if !approx_equals(&dist, &0.0) {
                         // there's something blocking the target from seeing
                         let hit = raycast::trace(
@@ -102,3 +125,35 @@ impl<'a> System<'a> for ObserveSystem {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn within_fov_accepts_straight_ahead() {
+        let forward = Vec3::new(0.0, 0.0, 1.0);
+        let dir = Vec3::new(0.0, 0.0, 5.0);
+
+        assert!(within_fov(&dir, &forward, dir.len(), FRAC_PI_2));
+    }
+
+    #[test]
+    fn within_fov_rejects_directly_behind() {
+        let forward = Vec3::new(0.0, 0.0, 1.0);
+        let dir = Vec3::new(0.0, 0.0, -5.0);
+
+        assert!(!within_fov(&dir, &forward, dir.len(), FRAC_PI_2));
+    }
+
+    #[test]
+    fn within_fov_is_exact_at_the_half_angle_boundary() {
+        let forward = Vec3::new(0.0, 0.0, 1.0);
+        let dir = Vec3::new(1.0, 0.0, 1.0);
+        let half_angle = FRAC_PI_2 / 2.0; // 45 degrees, exactly the angle of `dir`
+
+        assert!(within_fov(&dir, &forward, dir.len(), half_angle));
+        assert!(!within_fov(&dir, &forward, dir.len(), half_angle - 0.01));
+    }
+}